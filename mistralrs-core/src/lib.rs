@@ -0,0 +1,38 @@
+pub mod models;
+pub mod pipeline;
+pub mod sequence;
+pub mod utils;
+pub mod xlora_models;
+
+/// Immutably borrows a `Rc<RefCell<Sequence>>`, so call sites read like a plain
+/// field access instead of repeating `.borrow()` everywhere.
+#[macro_export]
+macro_rules! deref_refcell {
+    ($seq:expr) => {
+        $seq.borrow()
+    };
+}
+
+/// Mutably borrows a `Rc<RefCell<Sequence>>`. See [`deref_refcell`] for the
+/// read-only counterpart.
+#[macro_export]
+macro_rules! deref_mut_refcell {
+    ($seq:expr) => {
+        $seq.borrow_mut()
+    };
+}
+
+/// Loads a `ChatTemplate` from the loader's explicit override path when it was given
+/// one (e.g. a model whose chat template isn't bundled with its tokenizer config),
+/// and from `tokenizer_config.json` otherwise.
+#[macro_export]
+macro_rules! deserialize_chat_template {
+    ($paths:expr, $this:expr) => {{
+        let template_filename = match &$this.chat_template {
+            Some(template) => std::path::PathBuf::from(template),
+            None => $paths.get_template_filename().clone(),
+        };
+        let template_content = std::fs::read_to_string(&template_filename)?;
+        serde_json::from_str(&template_content)?
+    }};
+}
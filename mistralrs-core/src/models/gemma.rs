@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_nn::{Activation, Embedding, Linear, Module, VarBuilder};
+
+use crate::models::Cache;
+use crate::xlora_models::LoraLayerContext;
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub attention_bias: bool,
+    pub head_dim: usize,
+    pub hidden_act: Activation,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_attention_heads: usize,
+    pub num_hidden_layers: usize,
+    pub num_key_value_heads: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub vocab_size: usize,
+    pub max_position_embeddings: usize,
+}
+
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    // Gemma's checkpoints store `weight` such that the norm scale is `1.0 + weight`.
+    weight: Tensor,
+    eps: f64,
+}
+
+impl RmsNorm {
+    fn new(size: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        let weight = vb.get(size, "weight")?;
+        Ok(Self { weight, eps })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        candle_nn::ops::rms_norm(x, &self.weight.affine(1., 1.)?, self.eps as f32)
+    }
+}
+
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let gate_proj =
+            candle_nn::linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("gate_proj"))?;
+        let up_proj =
+            candle_nn::linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("up_proj"))?;
+        let down_proj =
+            candle_nn::linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act: cfg.hidden_act,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let gate = self.act.forward(&self.gate_proj.forward(x)?)?;
+        let up = self.up_proj.forward(x)?;
+        self.down_proj.forward(&(gate * up)?)
+    }
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f64,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / (freq_base as f32).powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), device)?;
+    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((MAX_SEQ_LEN, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?;
+    let sin = idx_theta.sin()?;
+    Ok((cos, sin))
+}
+
+fn rotate_half(x: &Tensor) -> Result<Tensor> {
+    let last_dim = x.dim(D::Minus1)?;
+    let x1 = x.narrow(D::Minus1, 0, last_dim / 2)?;
+    let x2 = x.narrow(D::Minus1, last_dim / 2, last_dim / 2)?;
+    Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)
+}
+
+fn apply_rotary_emb(x: &Tensor, cos: &Tensor, sin: &Tensor, index_pos: usize) -> Result<Tensor> {
+    let (_b_sz, _n_head, seq_len, _head_dim) = x.dims4()?;
+    let cos = cos.narrow(0, index_pos, seq_len)?;
+    let sin = sin.narrow(0, index_pos, seq_len)?;
+    let cos = cos.unsqueeze(0)?.unsqueeze(0)?;
+    let sin = sin.unsqueeze(0)?.unsqueeze(0)?;
+    let cos = Tensor::cat(&[&cos, &cos], D::Minus1)?;
+    let sin = Tensor::cat(&[&sin, &sin], D::Minus1)?;
+    (x.broadcast_mul(&cos)? + rotate_half(x)?.broadcast_mul(&sin)?)
+}
+
+/// Expands the grouped (shared) key/value heads up to the number of query heads by
+/// repeating each kv head `n_rep` times, so attention can be computed as plain
+/// multi-head attention after the fact. Shared with the quantized Gemma model, which
+/// uses the exact same grouped-query-attention layout.
+pub fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
+    if n_rep == 1 {
+        return Ok(x);
+    }
+    let (b_sz, n_kv_head, seq_len, head_dim) = x.dims4()?;
+    x.unsqueeze(2)?
+        .expand((b_sz, n_kv_head, n_rep, seq_len, head_dim))?
+        .reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    layer_idx: usize,
+}
+
+impl Attention {
+    fn new(cfg: &Config, layer_idx: usize, vb: VarBuilder) -> Result<Self> {
+        let q_dim = cfg.num_attention_heads * cfg.head_dim;
+        let kv_dim = cfg.num_key_value_heads * cfg.head_dim;
+        let linear = |in_dim, out_dim, vb: VarBuilder| -> Result<Linear> {
+            if cfg.attention_bias {
+                candle_nn::linear(in_dim, out_dim, vb)
+            } else {
+                candle_nn::linear_no_bias(in_dim, out_dim, vb)
+            }
+        };
+        Ok(Self {
+            q_proj: linear(cfg.hidden_size, q_dim, vb.pp("q_proj"))?,
+            k_proj: linear(cfg.hidden_size, kv_dim, vb.pp("k_proj"))?,
+            v_proj: linear(cfg.hidden_size, kv_dim, vb.pp("v_proj"))?,
+            o_proj: linear(q_dim, cfg.hidden_size, vb.pp("o_proj"))?,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.head_dim,
+            layer_idx,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        cos: &Tensor,
+        sin: &Tensor,
+        index_pos: usize,
+        cache: &Cache,
+        lora: Option<&LoraLayerContext>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let q = self.q_proj.forward(x)?;
+        let k = self.k_proj.forward(x)?;
+        let v = self.v_proj.forward(x)?;
+        let (q, k, v) = match lora {
+            None => (q, k, v),
+            Some(lora) => (
+                crate::xlora_models::apply_lora(&q, x, &lora.q_proj)?,
+                crate::xlora_models::apply_lora(&k, x, &lora.k_proj)?,
+                crate::xlora_models::apply_lora(&v, x, &lora.v_proj)?,
+            ),
+        };
+
+        let q = q
+            .reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = apply_rotary_emb(&q, cos, sin, index_pos)?.contiguous()?;
+        let k = apply_rotary_emb(&k, cos, sin, index_pos)?.contiguous()?;
+
+        let mut kvs = cache.lock();
+        let (k, v) = match &kvs[self.layer_idx] {
+            None => (k, v),
+            Some((cache_k, cache_v)) => {
+                let k = Tensor::cat(&[cache_k, &k], 2)?;
+                let v = Tensor::cat(&[cache_v, &v], 2)?;
+                (k, v)
+            }
+        };
+        kvs[self.layer_idx] = Some((k.clone(), v.clone()));
+        drop(kvs);
+
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = repeat_kv(k, n_rep)?;
+        let v = repeat_kv(v, n_rep)?;
+
+        let att = (q.matmul(&k.transpose(2, 3)?.contiguous()?)? / (self.head_dim as f64).sqrt())?;
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y
+            .transpose(1, 2)?
+            .reshape(&[b_sz, seq_len, self.num_heads * self.head_dim])?;
+        let out = self.o_proj.forward(&y)?;
+        match lora {
+            None => Ok(out),
+            Some(lora) => crate::xlora_models::apply_lora(&out, &y, &lora.o_proj),
+        }
+    }
+}
+
+struct DecoderLayer {
+    input_layernorm: RmsNorm,
+    self_attn: Attention,
+    post_attention_layernorm: RmsNorm,
+    mlp: Mlp,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, layer_idx: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            input_layernorm: RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb.pp("input_layernorm"),
+            )?,
+            self_attn: Attention::new(cfg, layer_idx, vb.pp("self_attn"))?,
+            post_attention_layernorm: RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb.pp("post_attention_layernorm"),
+            )?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        cos: &Tensor,
+        sin: &Tensor,
+        index_pos: usize,
+        cache: &Cache,
+        lora: Option<&LoraLayerContext>,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let h = self.input_layernorm.forward(x)?;
+        let h = self
+            .self_attn
+            .forward(&h, mask, cos, sin, index_pos, cache, lora)?;
+        let h = (h + residual)?;
+
+        let residual = &h;
+        let ffn_in = self.post_attention_layernorm.forward(&h)?;
+        (self.mlp.forward(&ffn_in)? + residual)
+    }
+}
+
+fn precomputed_mask(seq_len: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..seq_len)
+        .flat_map(|i| (0..seq_len).map(move |j| u8::from(j > i)))
+        .collect();
+    Tensor::from_slice(&mask, (seq_len, seq_len), device)
+}
+
+pub struct Model {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    cos: Tensor,
+    sin: Tensor,
+    masks: HashMap<usize, Tensor>,
+    pub config: Config,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for layer_idx in 0..cfg.num_hidden_layers {
+            layers.push(DecoderLayer::new(
+                cfg,
+                layer_idx,
+                vb_m.pp(format!("layers.{layer_idx}")),
+            )?);
+        }
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
+        // Gemma ties the LM head to the (scaled) input embedding table.
+        let lm_head = Linear::new(embed_tokens.embeddings().clone(), None);
+        let (cos, sin) = precomput_freqs_cis(cfg.head_dim, cfg.rope_theta, vb.device())?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            cos,
+            sin,
+            masks: HashMap::new(),
+            config: *cfg,
+            device: vb.device().clone(),
+            cache: Cache::new(cfg.num_hidden_layers, false),
+            max_seq_len: cfg.max_position_embeddings.max(MAX_SEQ_LEN),
+        })
+    }
+
+    /// Returns the (hidden_size-scaled) input token embeddings, before any transformer
+    /// blocks run. Used by the X-LoRA classifier, which predicts adapter scalings from
+    /// the input alone so it doesn't need a second full forward pass through the stack
+    /// to condition on hidden states.
+    pub fn embed_scaled(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let h = self.embed_tokens.forward(input_ids)?;
+        h * (self.config.hidden_size as f64).sqrt()
+    }
+
+    fn mask(&mut self, t: usize) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t) {
+            Ok(mask.clone())
+        } else {
+            let mask = precomputed_mask(t, &self.device)?;
+            self.masks.insert(t, mask.clone());
+            Ok(mask)
+        }
+    }
+
+    fn forward_core(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _seqlen_offsets_kernel: &Tensor,
+        lora: Option<&[LoraLayerContext]>,
+    ) -> Result<Tensor> {
+        let (_b_sz, seq_len) = input_ids.dims2()?;
+        let mask = if seq_len == 1 {
+            None
+        } else {
+            Some(self.mask(seq_len)?)
+        };
+        let index_pos = seqlen_offsets.first().copied().unwrap_or(0);
+
+        // Gemma scales the token embeddings by sqrt(hidden_size) before the first block.
+        let mut h = self.embed_tokens.forward(input_ids)?;
+        h = (h * (self.config.hidden_size as f64).sqrt())?;
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            h = layer.forward(
+                &h,
+                mask.as_ref(),
+                &self.cos,
+                &self.sin,
+                index_pos,
+                &self.cache,
+                lora.map(|lora| &lora[layer_idx]),
+            )?;
+        }
+        self.norm.forward(&h)
+    }
+
+    /// Runs the transformer stack and returns post-final-norm hidden states for every
+    /// position, without applying the LM head. Used by embedding extraction, which
+    /// pools over token positions rather than predicting the next token.
+    pub fn forward_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_kernel: &Tensor,
+    ) -> Result<Tensor> {
+        self.forward_core(input_ids, seqlen_offsets, seqlen_offsets_kernel, None)
+    }
+
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_kernel: &Tensor,
+    ) -> Result<Tensor> {
+        self.forward_with_lora(input_ids, seqlen_offsets, seqlen_offsets_kernel, None)
+    }
+
+    /// Same as [`Self::forward`], but blending in `lora`'s per-layer adapter
+    /// contributions (if any) into every self-attention projection along the way.
+    pub fn forward_with_lora(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_kernel: &Tensor,
+        lora: Option<&[LoraLayerContext]>,
+    ) -> Result<Tensor> {
+        let (_b_sz, seq_len) = input_ids.dims2()?;
+        let h = self.forward_core(input_ids, seqlen_offsets, seqlen_offsets_kernel, lora)?;
+        let h = h.i((.., seq_len - 1, ..))?;
+        self.lm_head.forward(&h)
+    }
+
+    /// Yields every weight tensor under its original HF/safetensors name, for
+    /// [`crate::pipeline::GemmaPipeline::quantize_and_save`] to re-quantize and
+    /// write out as a standalone GGUF file.
+    pub fn named_tensors(&self) -> Vec<(String, Tensor)> {
+        let mut out = Vec::new();
+        out.push((
+            "model.embed_tokens.weight".to_string(),
+            self.embed_tokens.embeddings().clone(),
+        ));
+        for (i, layer) in self.layers.iter().enumerate() {
+            out.push((
+                format!("model.layers.{i}.self_attn.q_proj.weight"),
+                layer.self_attn.q_proj.weight().clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.self_attn.k_proj.weight"),
+                layer.self_attn.k_proj.weight().clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.self_attn.v_proj.weight"),
+                layer.self_attn.v_proj.weight().clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.self_attn.o_proj.weight"),
+                layer.self_attn.o_proj.weight().clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.mlp.gate_proj.weight"),
+                layer.mlp.gate_proj.weight().clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.mlp.up_proj.weight"),
+                layer.mlp.up_proj.weight().clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.mlp.down_proj.weight"),
+                layer.mlp.down_proj.weight().clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.input_layernorm.weight"),
+                layer.input_layernorm.weight.clone(),
+            ));
+            out.push((
+                format!("model.layers.{i}.post_attention_layernorm.weight"),
+                layer.post_attention_layernorm.weight.clone(),
+            ));
+        }
+        out.push(("model.norm.weight".to_string(), self.norm.weight.clone()));
+        out
+    }
+}
@@ -0,0 +1,48 @@
+pub mod gemma;
+pub mod gemma2;
+pub mod quantized_gemma;
+
+use std::sync::{Mutex, MutexGuard};
+
+use candle_core::Tensor;
+
+/// Per-layer key/value cache shared across successive `forward` calls of the same
+/// pipeline, so decoding step `t` only computes attention for the new token instead
+/// of replaying the whole prefix. Guarded by a `Mutex` (rather than `RefCell`) since
+/// `Pipeline` is required to be `Send + Sync`.
+pub struct Cache {
+    kvs: Mutex<Vec<Option<(Tensor, Tensor)>>>,
+    is_xlora: bool,
+}
+
+impl Cache {
+    pub fn new(num_layers: usize, is_xlora: bool) -> Self {
+        Self {
+            kvs: Mutex::new(vec![None; num_layers]),
+            is_xlora,
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Vec<Option<(Tensor, Tensor)>>> {
+        self.kvs.lock().unwrap()
+    }
+
+    pub fn is_xlora(&self) -> bool {
+        self.is_xlora
+    }
+
+    /// Swaps in a freshly empty set of cache slots and returns the previous contents, so
+    /// a one-off forward pass that shares this `Cache` (e.g. embedding extraction) can
+    /// borrow the slots without disturbing an in-flight generation's keys/values. Pass
+    /// the return value to `restore` once that one-off pass is done.
+    pub fn take(&self) -> Vec<Option<(Tensor, Tensor)>> {
+        let mut kvs = self.kvs.lock().unwrap();
+        let num_layers = kvs.len();
+        std::mem::replace(&mut *kvs, vec![None; num_layers])
+    }
+
+    /// Restores cache contents previously returned by `take`.
+    pub fn restore(&self, kvs: Vec<Option<(Tensor, Tensor)>>) {
+        *self.kvs.lock().unwrap() = kvs;
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+use candle_core::quantized::{ggml_file, gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_nn::{Embedding, Module};
+
+use crate::models::Cache;
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    // Gemma's checkpoints store `weight` such that the norm scale is `1.0 + weight`.
+    weight: Tensor,
+    eps: f64,
+}
+
+impl RmsNorm {
+    fn from_qtensor(w: QTensor, eps: f64) -> Result<Self> {
+        let weight = w.dequantize(&w.device())?;
+        Ok(Self { weight, eps })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        candle_nn::ops::rms_norm(x, &self.weight.affine(1., 1.)?, self.eps as f32)
+    }
+}
+
+struct Mlp {
+    feed_forward_gate: QMatMul,
+    feed_forward_up: QMatMul,
+    feed_forward_down: QMatMul,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let gate = self.feed_forward_gate.forward(x)?.gelu()?;
+        let up = self.feed_forward_up.forward(x)?;
+        self.feed_forward_down.forward(&(gate * up)?)
+    }
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), device)?;
+    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((MAX_SEQ_LEN, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?;
+    let sin = idx_theta.sin()?;
+    Ok((cos, sin))
+}
+
+fn rotate_half(x: &Tensor) -> Result<Tensor> {
+    let last_dim = x.dim(D::Minus1)?;
+    let x1 = x.narrow(D::Minus1, 0, last_dim / 2)?;
+    let x2 = x.narrow(D::Minus1, last_dim / 2, last_dim / 2)?;
+    Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)
+}
+
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    attention_norm: RmsNorm,
+    mlp: Mlp,
+    ffn_norm: RmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    cos: Tensor,
+    sin: Tensor,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _head_dim) = x.dims4()?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        let cos = cos.unsqueeze(0)?.unsqueeze(0)?;
+        let sin = sin.unsqueeze(0)?.unsqueeze(0)?;
+        let cos = Tensor::cat(&[&cos, &cos], D::Minus1)?;
+        let sin = Tensor::cat(&[&sin, &sin], D::Minus1)?;
+        (x.broadcast_mul(&cos)? + rotate_half(x)?.broadcast_mul(&sin)?)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = x.dims3()?;
+
+        let q = self.attention_wq.forward(x)?;
+        let k = self.attention_wk.forward(x)?;
+        let v = self.attention_wv.forward(x)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = self.apply_rotary_emb(&q, index_pos)?.contiguous()?;
+        let k = self.apply_rotary_emb(&k, index_pos)?.contiguous()?;
+
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((cache_k, cache_v)) => {
+                let k = Tensor::cat(&[cache_k, &k], 2)?;
+                let v = Tensor::cat(&[cache_v, &v], 2)?;
+                (k, v)
+            }
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let n_rep = self.n_head / self.n_kv_head;
+        let k = crate::models::gemma::repeat_kv(k, n_rep)?;
+        let v = crate::models::gemma::repeat_kv(v, n_rep)?;
+
+        let att = (q.matmul(&k.transpose(2, 3)?.contiguous()?)? / (self.head_dim as f64).sqrt())?;
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y
+            .transpose(1, 2)?
+            .reshape(&[b_sz, seq_len, self.n_head * self.head_dim])?;
+        self.attention_wo.forward(&y)
+    }
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    masks: HashMap<usize, Tensor>,
+    hidden_size: usize,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+}
+
+fn precomputed_mask(seq_len: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..seq_len)
+        .flat_map(|i| (0..seq_len).map(move |j| u8::from(j > i)))
+        .collect();
+    Tensor::from_slice(&mask, (seq_len, seq_len), device)
+}
+
+impl ModelWeights {
+    fn mask(&mut self, t: usize) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t) {
+            Ok(mask.clone())
+        } else {
+            let mask = precomputed_mask(t, &self.device)?;
+            self.masks.insert(t, mask.clone());
+            Ok(mask)
+        }
+    }
+
+    /// Builds a Gemma model from a (legacy) GGML container. The token embeddings and norm
+    /// weights are dequantized; the attention/MLP projections and the output head stay
+    /// quantized and run through `QMatMul`.
+    pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize, device: &Device) -> Result<Self> {
+        let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
+        let (cos, sin) = precomput_freqs_cis(head_dim, 10000., device)?;
+        let tok_embeddings = ct.remove("tok_embeddings.weight")?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let norm = RmsNorm::from_qtensor(ct.remove("norm.weight")?, 1e-5)?;
+        let output = ct.remove("output.weight")?;
+        let mut layers = Vec::with_capacity(ct.hparams.n_layer as usize);
+        for layer_idx in 0..ct.hparams.n_layer {
+            let prefix = format!("layers.{layer_idx}");
+            let attention_wq = ct.remove(&format!("{prefix}.attention.wq.weight"))?;
+            let attention_wk = ct.remove(&format!("{prefix}.attention.wk.weight"))?;
+            let attention_wv = ct.remove(&format!("{prefix}.attention.wv.weight"))?;
+            let attention_wo = ct.remove(&format!("{prefix}.attention.wo.weight"))?;
+            let mlp = Mlp {
+                feed_forward_gate: QMatMul::from_qtensor(
+                    ct.remove(&format!("{prefix}.feed_forward.w1.weight"))?,
+                )?,
+                feed_forward_up: QMatMul::from_qtensor(
+                    ct.remove(&format!("{prefix}.feed_forward.w3.weight"))?,
+                )?,
+                feed_forward_down: QMatMul::from_qtensor(
+                    ct.remove(&format!("{prefix}.feed_forward.w2.weight"))?,
+                )?,
+            };
+            let attention_norm = RmsNorm::from_qtensor(
+                ct.remove(&format!("{prefix}.attention_norm.weight"))?,
+                1e-5,
+            )?;
+            let ffn_norm =
+                RmsNorm::from_qtensor(ct.remove(&format!("{prefix}.ffn_norm.weight"))?, 1e-5)?;
+            layers.push(LayerWeights {
+                attention_wq: QMatMul::from_qtensor(attention_wq)?,
+                attention_wk: QMatMul::from_qtensor(attention_wk)?,
+                attention_wv: QMatMul::from_qtensor(attention_wv)?,
+                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_norm,
+                mlp,
+                ffn_norm,
+                n_head: ct.hparams.n_head as usize,
+                n_kv_head: ct.hparams.n_head as usize / gqa,
+                head_dim,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                kv_cache: None,
+            });
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, ct.hparams.n_embd as usize),
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            masks: HashMap::new(),
+            hidden_size: ct.hparams.n_embd as usize,
+            device: device.clone(),
+            cache: Cache::new(ct.hparams.n_layer as usize, false),
+            max_seq_len: MAX_SEQ_LEN,
+        })
+    }
+
+    /// Builds a Gemma model from a parsed GGUF container (the format produced by `llama.cpp` /
+    /// `convert-hf-to-gguf.py`), taking hyperparameters from its metadata KV pairs and reading
+    /// each tensor from `reader`. The token embeddings and norm weights are dequantized; the
+    /// attention/MLP projections and the output head stay in their stored block type (including
+    /// the k-quants Q4_K/Q5_K/Q6_K as well as Q8_0) and run through `QMatMul`.
+    pub fn from_gguf<R: Read + Seek>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        let head_count = md_get("gemma.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("gemma.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("gemma.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("gemma.embedding_length")?.to_u32()? as usize;
+        let rope_freq_base = md_get("gemma.rope.freq_base")
+            .and_then(|v| v.to_f32())
+            .unwrap_or(10000f32);
+        let rms_norm_eps = md_get("gemma.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let head_dim = md_get("gemma.attention.key_length")
+            .and_then(|v| Ok(v.to_u32()? as usize))
+            .unwrap_or(embedding_length / head_count);
+
+        let (cos, sin) = precomput_freqs_cis(head_dim, rope_freq_base, device)?;
+
+        let tok_embeddings_q = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings_q.dequantize(device)?;
+        let norm = RmsNorm::from_qtensor(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        let output = match ct.tensor(reader, "output.weight", device) {
+            Ok(t) => t,
+            // Gemma checkpoints commonly tie the LM head to the input embedding.
+            Err(_) => ct.tensor(reader, "token_embd.weight", device)?,
+        };
+
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
+            let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
+            let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
+            let attention_wo =
+                ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
+            let mlp = Mlp {
+                feed_forward_gate: QMatMul::from_qtensor(ct.tensor(
+                    reader,
+                    &format!("{prefix}.ffn_gate.weight"),
+                    device,
+                )?)?,
+                feed_forward_up: QMatMul::from_qtensor(ct.tensor(
+                    reader,
+                    &format!("{prefix}.ffn_up.weight"),
+                    device,
+                )?)?,
+                feed_forward_down: QMatMul::from_qtensor(ct.tensor(
+                    reader,
+                    &format!("{prefix}.ffn_down.weight"),
+                    device,
+                )?)?,
+            };
+            let attention_norm = RmsNorm::from_qtensor(
+                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?,
+                rms_norm_eps,
+            )?;
+            let ffn_norm = RmsNorm::from_qtensor(
+                ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?,
+                rms_norm_eps,
+            )?;
+            layers.push(LayerWeights {
+                attention_wq: QMatMul::from_qtensor(attention_wq)?,
+                attention_wk: QMatMul::from_qtensor(attention_wk)?,
+                attention_wv: QMatMul::from_qtensor(attention_wv)?,
+                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_norm,
+                mlp,
+                ffn_norm,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                kv_cache: None,
+            });
+        }
+
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            masks: HashMap::new(),
+            hidden_size: embedding_length,
+            device: device.clone(),
+            cache: Cache::new(block_count, false),
+            max_seq_len: MAX_SEQ_LEN,
+        })
+    }
+
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        seqlen_offsets: &[usize],
+        _seqlen_offsets_kernel: &Tensor,
+    ) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mask = if seq_len == 1 {
+            None
+        } else {
+            Some(self.mask(seq_len)?)
+        };
+        let index_pos = seqlen_offsets.first().copied().unwrap_or(0);
+
+        // Gemma scales the token embeddings by sqrt(hidden_size) before the first block.
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        layer_in = (layer_in * (self.hidden_size as f64).sqrt())?;
+        for layer in self.layers.iter_mut() {
+            let residual = &layer_in;
+            let x = layer.attention_norm.forward(&layer_in)?;
+            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
+            let x = (attn + residual)?;
+
+            let residual = &x;
+            let x_ffn = layer.ffn_norm.forward(&x)?;
+            let x = (layer.mlp.forward(&x_ffn)? + residual)?;
+            layer_in = x;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        self.output.forward(&x)
+    }
+}
@@ -2,18 +2,23 @@ use super::{
     calculate_inputs, get_completion_input, get_model_paths, get_prompt_input, get_xlora_paths,
     Loader, ModelKind, ModelPaths, Pipeline, TokenSource, XLoraPaths,
 };
+use crate::models::quantized_gemma::ModelWeights as QModelWeights;
 use crate::models::Cache;
+use crate::pipeline::text_stream::TokenOutputStream;
 use crate::pipeline::ChatTemplate;
-use crate::xlora_models::{XLoraConfig, XLoraGemma};
+use crate::xlora_models::{XLoraConfig, XLoraGemma, XLoraGemma2};
 use crate::{deref_mut_refcell, deref_refcell, deserialize_chat_template};
 use crate::{
     models::gemma::{Config, Model as NormalModel},
+    models::gemma2::{Config as Gemma2Config, Model as Gemma2Model},
     sequence::Sequence,
     utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors},
 };
 use anyhow::Result;
-use candle_core::{DType, Device, Tensor};
-use candle_sampling::logits_processor::Logprobs;
+use candle_core::quantized::gguf_file::Value as GgufValue;
+use candle_core::quantized::{ggml_file, gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device, IndexOp, Tensor, D};
+use candle_sampling::logits_processor::{LogitsProcessor, Logprobs, Sampling};
 use either::Either;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_lora::{LoraConfig, Ordering};
@@ -22,7 +27,8 @@ use serde_json::Value;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::{rc::Rc, sync::Mutex};
 use thiserror::Error;
@@ -31,6 +37,9 @@ use tokenizers::Tokenizer;
 enum Model {
     Normal(NormalModel),
     XLoraNormal(XLoraGemma),
+    QuantizedNormal(QModelWeights),
+    Gemma2(Gemma2Model),
+    XLoraGemma2(XLoraGemma2),
 }
 
 pub struct GemmaModelPaths<P> {
@@ -78,6 +87,7 @@ impl ModelPaths for GemmaModelPaths<PathBuf> {
 pub struct GemmaPipeline {
     model: Model,
     tokenizer: Tokenizer,
+    token_stream: TokenOutputStream,
     config: GemmaSpecificConfig,
     no_kv_cache: bool,
     chat_template: ChatTemplate,
@@ -99,12 +109,109 @@ pub struct GemmaLoader {
 #[derive(Clone, Copy)]
 pub struct GemmaSpecificConfig {
     pub repeat_last_n: usize,
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub repeat_penalty: Option<f32>,
 }
 
 fn default_max_position_embeddings() -> usize {
     4096
 }
 
+const SAMPLING_SEED: u64 = 299792458;
+
+/// How to collapse a sequence's per-token hidden states into a single embedding vector,
+/// with optional L2 normalization so the result can be used directly for cosine similarity.
+#[derive(Clone, Copy)]
+pub enum Pooling {
+    /// Average the hidden state across every (non-padding) token position.
+    Mean { normalize: bool },
+    /// Take the hidden state of the final token, e.g. for causal LMs used as embedders.
+    Last { normalize: bool },
+    /// Take the hidden state of the first token, as in BERT-style `[CLS]` pooling.
+    Cls { normalize: bool },
+}
+
+impl Pooling {
+    fn normalize(&self) -> bool {
+        match self {
+            Pooling::Mean { normalize }
+            | Pooling::Last { normalize }
+            | Pooling::Cls { normalize } => *normalize,
+        }
+    }
+}
+
+/// Target block-quantization scheme for [`GemmaPipeline::quantize_and_save`].
+#[derive(Clone, Copy)]
+pub enum QuantType {
+    Q4_0,
+    Q4K,
+    Q8_0,
+}
+
+impl QuantType {
+    fn to_ggml(self) -> GgmlDType {
+        match self {
+            QuantType::Q4_0 => GgmlDType::Q4_0,
+            QuantType::Q4K => GgmlDType::Q4K,
+            QuantType::Q8_0 => GgmlDType::Q8_0,
+        }
+    }
+}
+
+/// Penalizes tokens that already appeared in `context`, matching the standard
+/// repetition-penalty formula: positive logits are divided by the penalty, negative
+/// logits are multiplied by it, so repeated tokens become less likely either way.
+fn apply_repeat_penalty(logits: &Tensor, penalty: f32, context: &[u32]) -> Result<Tensor> {
+    let device = logits.device();
+    let mut logits = logits.to_vec1::<f32>()?;
+    let mut already_seen = HashMap::new();
+    for &tok in context {
+        *already_seen.entry(tok).or_insert(0usize) += 1;
+    }
+    for (tok, _count) in already_seen {
+        if let Some(logit) = logits.get_mut(tok as usize) {
+            *logit = if *logit >= 0. {
+                *logit / penalty
+            } else {
+                *logit * penalty
+            };
+        }
+    }
+    let len = logits.len();
+    Ok(Tensor::from_vec(logits, len, device)?)
+}
+
+/// Maps a tensor's HF/safetensors name (as produced by [`crate::models::gemma::Model::named_tensors`])
+/// to the llama.cpp/GGUF naming convention that [`QModelWeights::from_gguf`] expects, so a
+/// file written by [`GemmaPipeline::quantize_and_save`] can be read back by our own loader.
+fn gguf_tensor_name(name: &str) -> Option<String> {
+    if let Some(rest) = name.strip_prefix("model.layers.") {
+        let (idx, rest) = rest.split_once('.')?;
+        let mapped = match rest {
+            "self_attn.q_proj.weight" => "attn_q.weight",
+            "self_attn.k_proj.weight" => "attn_k.weight",
+            "self_attn.v_proj.weight" => "attn_v.weight",
+            "self_attn.o_proj.weight" => "attn_output.weight",
+            "mlp.gate_proj.weight" => "ffn_gate.weight",
+            "mlp.up_proj.weight" => "ffn_up.weight",
+            "mlp.down_proj.weight" => "ffn_down.weight",
+            "input_layernorm.weight" => "attn_norm.weight",
+            "post_attention_layernorm.weight" => "ffn_norm.weight",
+            _ => return None,
+        };
+        return Some(format!("blk.{idx}.{mapped}"));
+    }
+    match name {
+        "model.embed_tokens.weight" => Some("token_embd.weight".to_string()),
+        "model.norm.weight" => Some("output_norm.weight".to_string()),
+        "lm_head.weight" => Some("output.weight".to_string()),
+        _ => None,
+    }
+}
+
 #[derive(Deserialize)]
 pub struct BasicConfig {
     pub attention_bias: bool,
@@ -121,6 +228,19 @@ pub struct BasicConfig {
 
     #[serde(default = "default_max_position_embeddings")]
     pub max_position_embeddings: usize,
+
+    // Gemma2 fields. A `sliding_window` entry (or `model_type: "gemma2"`) is what
+    // distinguishes a Gemma2 `config.json` from a v1 Gemma one.
+    #[serde(default)]
+    pub model_type: Option<String>,
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    #[serde(default)]
+    pub attn_logit_softcapping: Option<f64>,
+    #[serde(default)]
+    pub final_logit_softcapping: Option<f64>,
+    #[serde(default)]
+    pub query_pre_attn_scalar: Option<f64>,
 }
 
 #[derive(Error, Debug)]
@@ -242,6 +362,31 @@ impl Loader for GemmaLoader {
             attention_bias: basic_config.attention_bias,
             head_dim: basic_config.head_dim,
         };
+        // Gemma2 adds sliding-window/global attention alternation and logit soft-capping;
+        // a `sliding_window` entry (or an explicit `model_type`) is what config.json uses
+        // to tell the two architectures apart.
+        let is_gemma2 = basic_config.model_type.as_deref() == Some("gemma2")
+            || basic_config.sliding_window.is_some();
+        let gemma2_config = Gemma2Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            rope_theta: basic_config.rope_theta,
+            attention_bias: basic_config.attention_bias,
+            head_dim: basic_config.head_dim,
+            sliding_window: basic_config.sliding_window.unwrap_or(4096),
+            attn_logit_softcapping: basic_config.attn_logit_softcapping,
+            final_logit_softcapping: basic_config.final_logit_softcapping,
+            query_pre_attn_scalar: basic_config
+                .query_pre_attn_scalar
+                .unwrap_or(basic_config.head_dim as f64),
+        };
         let default_dtype = if device.is_cuda() {
             DType::BF16
         } else {
@@ -249,10 +394,24 @@ impl Loader for GemmaLoader {
         };
 
         println!("Loading model on {device:?}...");
-        let model = match self.kind {
-            ModelKind::QuantizedGGUF => unreachable!(),
-            ModelKind::QuantizedGGML => unreachable!(),
-            ModelKind::Normal => {
+        let model = match (self.kind, is_gemma2) {
+            (ModelKind::QuantizedGGUF, _) => {
+                let weights_filename = &paths.get_weight_filenames()[0];
+                let mut file = File::open(weights_filename)?;
+                let gguf = gguf_file::Content::read(&mut file)?;
+                let model = QModelWeights::from_gguf(gguf, &mut file, device)?;
+                Model::QuantizedNormal(model)
+            }
+            (ModelKind::QuantizedGGML, _) => {
+                let weights_filename = &paths.get_weight_filenames()[0];
+                let mut file = File::open(weights_filename)?;
+                let ggml = ggml_file::Content::read(&mut file, device)?;
+                // Gemma uses full multi-head attention (no grouped-query sharing) when
+                // converted from an older GGML checkpoint, hence a GQA factor of 1.
+                let model = QModelWeights::from_ggml(ggml, 1, device)?;
+                Model::QuantizedNormal(model)
+            }
+            (ModelKind::Normal, false) => {
                 let vb = from_mmaped_safetensors(
                     paths.get_weight_filenames().to_vec(),
                     Vec::new(),
@@ -264,7 +423,19 @@ impl Loader for GemmaLoader {
                 let model = NormalModel::new(&config, vb)?;
                 Model::Normal(model)
             }
-            ModelKind::XLoraNormal => {
+            (ModelKind::Normal, true) => {
+                let vb = from_mmaped_safetensors(
+                    paths.get_weight_filenames().to_vec(),
+                    Vec::new(),
+                    dtype.unwrap_or(default_dtype),
+                    device,
+                    false,
+                )?;
+
+                let model = Gemma2Model::new(&gemma2_config, vb)?;
+                Model::Gemma2(model)
+            }
+            (ModelKind::XLoraNormal, false) => {
                 let mut safetensors_paths = paths.get_weight_filenames().iter().collect::<Vec<_>>();
                 safetensors_paths.push(paths.get_classifier_path().as_ref().unwrap());
                 let vb = from_mmaped_safetensors(
@@ -293,8 +464,37 @@ impl Loader for GemmaLoader {
                 )?;
                 Model::XLoraNormal(model)
             }
-            ModelKind::XLoraGGUF => unreachable!(),
-            ModelKind::XLoraGGML => unreachable!(),
+            (ModelKind::XLoraNormal, true) => {
+                let mut safetensors_paths = paths.get_weight_filenames().iter().collect::<Vec<_>>();
+                safetensors_paths.push(paths.get_classifier_path().as_ref().unwrap());
+                let vb = from_mmaped_safetensors(
+                    safetensors_paths
+                        .iter()
+                        .map(|x| (*x).to_owned())
+                        .collect::<Vec<_>>(),
+                    paths
+                        .get_adapter_filenames()
+                        .as_ref()
+                        .unwrap()
+                        .iter()
+                        .map(|(_, x)| (*x).to_owned())
+                        .collect::<Vec<_>>(),
+                    dtype.unwrap_or(default_dtype),
+                    device,
+                    false,
+                )?;
+
+                let model = XLoraGemma2::new(
+                    &gemma2_config,
+                    vb,
+                    paths.get_adapter_configs().as_ref().unwrap(),
+                    paths.get_classifier_config().as_ref().unwrap().clone(),
+                    paths.get_ordering().as_ref().unwrap().clone(),
+                )?;
+                Model::XLoraGemma2(model)
+            }
+            (ModelKind::XLoraGGUF, _) => unreachable!(),
+            (ModelKind::XLoraGGML, _) => unreachable!(),
         };
         println!("Model loaded.");
 
@@ -304,6 +504,7 @@ impl Loader for GemmaLoader {
         let chat_template: ChatTemplate = deserialize_chat_template!(paths, self);
 
         Ok(Box::new(Mutex::new(GemmaPipeline {
+            token_stream: TokenOutputStream::new(tokenizer.clone()),
             model,
             tokenizer,
             config: self.config,
@@ -313,6 +514,178 @@ impl Loader for GemmaLoader {
     }
 }
 
+impl GemmaPipeline {
+    /// Runs the transformer stack and returns pooled, fixed-size embedding vectors instead
+    /// of next-token logits, so Gemma can back a vector-search index alongside keyword search.
+    pub fn embed(
+        &mut self,
+        input_toks: Box<[Rc<RefCell<Sequence>>]>,
+        pooling: Pooling,
+    ) -> Result<Tensor> {
+        // `calculate_inputs` right-pads every sequence up to the batch's longest one, so
+        // we need each row's real (pre-padding) length to pool correctly below.
+        let lengths: Vec<usize> = input_toks
+            .iter()
+            .map(|seq| deref_refcell!(seq).get_toks().len())
+            .collect();
+        let (input_ids, _, seqlen_offsets, _, seqlen_offsets_kernel, _) = calculate_inputs(
+            input_toks,
+            true,
+            self.is_xlora(),
+            self.device(),
+            self.no_kv_cache,
+        );
+        // `forward_hidden_states` runs through the same `Cache` that ordinary `forward`
+        // calls use for incremental KV caching, so calling it here on a pipeline that's
+        // also serving generation requests would corrupt any in-flight decode's cache
+        // (and vice versa). Borrow the cache slots for just this one-off pass and put the
+        // real contents back afterwards instead of aliasing live generation state.
+        let hidden_states = match self.model {
+            Model::Normal(ref mut model) => {
+                let saved_cache = model.cache.take();
+                let result = model.forward_hidden_states(
+                    &input_ids,
+                    &seqlen_offsets,
+                    &seqlen_offsets_kernel,
+                );
+                model.cache.restore(saved_cache);
+                result?
+            }
+            Model::Gemma2(ref mut model) => {
+                let saved_cache = model.cache.take();
+                let result = model.forward_hidden_states(
+                    &input_ids,
+                    &seqlen_offsets,
+                    &seqlen_offsets_kernel,
+                );
+                model.cache.restore(saved_cache);
+                result?
+            }
+            Model::QuantizedNormal(_) => {
+                anyhow::bail!(
+                    "embedding extraction is not supported for quantized Gemma models yet"
+                )
+            }
+            Model::XLoraNormal(_) | Model::XLoraGemma2(_) => {
+                anyhow::bail!("embedding extraction is not supported for X-LoRA Gemma models")
+            }
+        };
+
+        let pooled = match pooling {
+            Pooling::Mean { .. } => {
+                let rows = lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &len)| Ok(hidden_states.i(i)?.narrow(0, 0, len)?.mean(0)?.unsqueeze(0)?))
+                    .collect::<Result<Vec<_>>>()?;
+                Tensor::cat(&rows, 0)?
+            }
+            Pooling::Last { .. } => {
+                let rows = lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &len)| Ok(hidden_states.i((i, len - 1))?.unsqueeze(0)?))
+                    .collect::<Result<Vec<_>>>()?;
+                Tensor::cat(&rows, 0)?
+            }
+            Pooling::Cls { .. } => hidden_states.i((.., 0, ..))?,
+        };
+
+        if pooling.normalize() {
+            let norm = pooled.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+            Ok(pooled.broadcast_div(&norm)?)
+        } else {
+            Ok(pooled)
+        }
+    }
+
+    /// Quantizes the currently-loaded full-precision weights and writes them out as a
+    /// standalone GGUF file, so later starts can load the quantized artifact directly
+    /// instead of re-downloading and re-converting the original safetensors checkpoint.
+    pub fn quantize_and_save(&self, out: &Path, target: QuantType) -> Result<()> {
+        let model = match &self.model {
+            Model::Normal(model) => model,
+            Model::QuantizedNormal(_) => anyhow::bail!("model is already quantized"),
+            Model::XLoraNormal(_) | Model::XLoraGemma2(_) => {
+                anyhow::bail!("cannot quantize an X-LoRA model")
+            }
+            Model::Gemma2(_) => anyhow::bail!("quantizing Gemma2 models is not yet supported"),
+        };
+
+        let mut tensors = Vec::new();
+        for (name, tensor) in model.named_tensors() {
+            // Norms and the embedding table are small and precision-sensitive; only the
+            // large attention/MLP projection matrices are worth block-quantizing.
+            let dtype = if name.contains("norm") || name.contains("embed_tokens") {
+                GgmlDType::F32
+            } else {
+                target.to_ggml()
+            };
+            // `from_gguf` reads llama.cpp-convention tensor names (`blk.N....`,
+            // `token_embd.weight`, ...), not the HF/safetensors names `named_tensors`
+            // yields, so every tensor has to be renamed before it can round-trip back
+            // through our own GGUF loader.
+            let gguf_name = gguf_tensor_name(&name)
+                .ok_or_else(|| anyhow::anyhow!("no GGUF name mapping for tensor `{name}`"))?;
+            tensors.push((gguf_name, QTensor::quantize(&tensor, dtype)?));
+        }
+
+        let metadata = [
+            (
+                "general.architecture",
+                GgufValue::String("gemma".to_string()),
+            ),
+            (
+                "gemma.context_length",
+                GgufValue::U32(model.max_seq_len as u32),
+            ),
+            (
+                "gemma.embedding_length",
+                GgufValue::U32(model.config.hidden_size as u32),
+            ),
+            (
+                "gemma.block_count",
+                GgufValue::U32(model.config.num_hidden_layers as u32),
+            ),
+            (
+                "gemma.attention.head_count",
+                GgufValue::U32(model.config.num_attention_heads as u32),
+            ),
+            (
+                "gemma.attention.head_count_kv",
+                GgufValue::U32(model.config.num_key_value_heads as u32),
+            ),
+            (
+                "gemma.attention.layer_norm_rms_epsilon",
+                GgufValue::F32(model.config.rms_norm_eps as f32),
+            ),
+            (
+                // `from_gguf` falls back to `embedding_length / head_count` when this is
+                // absent, which only happens to be right when head_dim is the "default"
+                // hidden_size/num_attention_heads - not true for real Gemma checkpoints
+                // (e.g. Gemma-7B: hidden_size=3072, num_attention_heads=16 gives 192, but
+                // the real head_dim is 256). Write the real value so the loader never
+                // has to guess.
+                "gemma.attention.key_length",
+                GgufValue::U32(model.config.head_dim as u32),
+            ),
+            (
+                "gemma.rope.freq_base",
+                GgufValue::F32(model.config.rope_theta as f32),
+            ),
+        ];
+        let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+        let tensors = tensors
+            .iter()
+            .map(|(name, qtensor)| (name.as_str(), qtensor))
+            .collect::<Vec<_>>();
+
+        let mut file = fs::File::create(out)?;
+        gguf_file::write(&mut file, &metadata, &tensors)?;
+        Ok(())
+    }
+}
+
 impl Pipeline for GemmaPipeline {
     fn forward(&mut self, input_toks: Box<[Rc<RefCell<Sequence>>]>, is_prompt: bool) -> Tensor {
         let (
@@ -333,6 +706,12 @@ impl Pipeline for GemmaPipeline {
             Model::Normal(ref mut model) => {
                 model.forward(&input_ids, &seqlen_offsets, &seqlen_offsets_kernel)
             }
+            Model::QuantizedNormal(ref mut model) => {
+                model.forward(&input_ids, &seqlen_offsets, &seqlen_offsets_kernel)
+            }
+            Model::Gemma2(ref mut model) => {
+                model.forward(&input_ids, &seqlen_offsets, &seqlen_offsets_kernel)
+            }
             Model::XLoraNormal(ref mut model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap(),
@@ -342,6 +721,15 @@ impl Pipeline for GemmaPipeline {
                 seqlen_offsets_full_kernel.unwrap(),
                 self.no_kv_cache,
             ),
+            Model::XLoraGemma2(ref mut model) => model.forward(
+                &input_ids,
+                input_ids_full.as_ref().unwrap(),
+                &seqlen_offsets,
+                seqlen_offsets_full.as_ref().unwrap(),
+                &seqlen_offsets_kernel,
+                seqlen_offsets_full_kernel.unwrap(),
+                self.no_kv_cache,
+            ),
         };
         match result {
             Ok(v) => v,
@@ -353,7 +741,10 @@ impl Pipeline for GemmaPipeline {
     fn device(&self) -> &Device {
         match self.model {
             Model::Normal(ref model) => &model.device,
+            Model::QuantizedNormal(ref model) => &model.device,
+            Model::Gemma2(ref model) => &model.device,
             Model::XLoraNormal(ref model) => &model.device,
+            Model::XLoraGemma2(ref model) => &model.device,
         }
     }
     fn num_hidden_layers(&self) -> usize {
@@ -362,7 +753,10 @@ impl Pipeline for GemmaPipeline {
     fn cache(&self) -> &Cache {
         match self.model {
             Model::Normal(ref model) => &model.cache,
+            Model::QuantizedNormal(ref model) => &model.cache,
+            Model::Gemma2(ref model) => &model.cache,
             Model::XLoraNormal(ref model) => &model.cache,
+            Model::XLoraGemma2(ref model) => &model.cache,
         }
     }
     fn sample(&mut self, logits: Tensor, seq: Rc<RefCell<Sequence>>) -> Result<Logprobs> {
@@ -379,9 +773,40 @@ impl Pipeline for GemmaPipeline {
             .saturating_sub(self.config.repeat_last_n);
         let ctxt = deref_refcell!(seq).get_toks()[start_at..].to_vec();
 
-        Ok(deref_mut_refcell!(seq)
-            .logits_processor()
-            .sample(&logits, Some(&ctxt))?)
+        let logits = match self.config.repeat_penalty {
+            Some(penalty) if penalty != 1. => apply_repeat_penalty(&logits, penalty, &ctxt)?,
+            _ => logits,
+        };
+
+        // Each sequence owns its own `LogitsProcessor` so concurrent/sequential requests
+        // never share an RNG stream. Only when this pipeline's config sets a temperature
+        // (top-k/top-p apply on top of it and are ignored without one) do we swap in an
+        // independently-seeded one for this
+        // sequence - but only on the first token: `configure_sampling_once` leaves an
+        // already-configured processor alone, so the same RNG state keeps advancing
+        // across every later decode step instead of being rebuilt (and reseeded to the
+        // same value) per call.
+        match (
+            self.config.temperature,
+            self.config.top_k,
+            self.config.top_p,
+        ) {
+            (None, _, _) => Ok(deref_mut_refcell!(seq)
+                .logits_processor()
+                .sample(&logits, Some(&ctxt))?),
+            (Some(temperature), top_k, top_p) => {
+                let sampling = match (top_k, top_p) {
+                    (None, None) => Sampling::All { temperature },
+                    (Some(k), None) => Sampling::TopK { k, temperature },
+                    (None, Some(p)) => Sampling::TopP { p, temperature },
+                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+                };
+                let seed = SAMPLING_SEED ^ (Rc::as_ptr(&seq) as u64);
+                Ok(deref_mut_refcell!(seq)
+                    .configure_sampling_once(|| LogitsProcessor::from_sampling(seed, sampling))
+                    .sample(&logits, Some(&ctxt))?)
+            }
+        }
     }
     fn tokenizer(&self) -> Tokenizer {
         self.tokenizer.clone()
@@ -398,18 +823,24 @@ impl Pipeline for GemmaPipeline {
             .unwrap_or_else(|| panic!("Unable to extract `{eos_tok}` EOS token."))
     }
     fn name(&self) -> &'static str {
-        "gemma"
+        match &self.model {
+            Model::Normal(_) | Model::QuantizedNormal(_) | Model::XLoraNormal(_) => "gemma",
+            Model::Gemma2(_) | Model::XLoraGemma2(_) => "gemma2",
+        }
     }
     fn get_max_seq_len(&self) -> usize {
         match &self.model {
             Model::Normal(model) => model.max_seq_len,
+            Model::QuantizedNormal(model) => model.max_seq_len,
+            Model::Gemma2(model) => model.max_seq_len,
             Model::XLoraNormal(model) => model.max_seq_len,
+            Model::XLoraGemma2(model) => model.max_seq_len,
         }
     }
     fn is_xlora(&self) -> bool {
         match &self.model {
-            Model::Normal(_) => false,
-            Model::XLoraNormal(_) => true,
+            Model::Normal(_) | Model::Gemma2(_) | Model::QuantizedNormal(_) => false,
+            Model::XLoraNormal(_) | Model::XLoraGemma2(_) => true,
         }
     }
     fn has_no_kv_cache(&self) -> bool {
@@ -418,4 +849,10 @@ impl Pipeline for GemmaPipeline {
     fn get_chat_template(&self) -> &ChatTemplate {
         &self.chat_template
     }
+    /// Feeds `tok` into the incremental UTF-8-safe detokenizer, returning the newly
+    /// decodable text fragment (if the token completed one) so callers can stream
+    /// output without waiting for the full sequence.
+    fn step_decode(&mut self, tok: u32) -> Option<String> {
+        self.token_stream.next_token(tok).unwrap_or(None)
+    }
 }
@@ -0,0 +1,240 @@
+mod gemma;
+mod text_stream;
+
+pub use gemma::{
+    GemmaLoader, GemmaModelPaths, GemmaPipeline, GemmaSpecificConfig, Pooling, QuantType,
+};
+
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_sampling::logits_processor::Logprobs;
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::sequence::Sequence;
+use mistralrs_lora::{LoraConfig, Ordering};
+
+/// Which kind of checkpoint a [`Loader`] should build a [`Pipeline`] from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelKind {
+    Normal,
+    QuantizedGGUF,
+    QuantizedGGML,
+    XLoraNormal,
+    XLoraGGUF,
+    XLoraGGML,
+}
+
+/// Where to look up the HF Hub auth token from.
+#[derive(Clone, Debug)]
+pub enum TokenSource {
+    Literal(String),
+    EnvVar(String),
+    CacheToken,
+    None,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ChatTemplate {
+    #[serde(default)]
+    pub eos_token: either::Either<String, AddedTokenWrapper>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AddedTokenWrapper {
+    pub content: String,
+}
+
+impl Default for either::Either<String, AddedTokenWrapper> {
+    fn default() -> Self {
+        either::Either::Left(String::new())
+    }
+}
+
+/// The set of files resolved for a non-quantized/X-LoRA adapter.
+pub struct XLoraPaths {
+    pub adapter_configs: Option<Vec<(String, LoraConfig)>>,
+    pub adapter_safetensors: Option<Vec<(String, PathBuf)>>,
+    pub classifier_path: Option<PathBuf>,
+    pub xlora_order: Option<Ordering>,
+    pub xlora_config: Option<crate::xlora_models::XLoraConfig>,
+}
+
+/// Paths on disk (or downloaded from the Hub) needed to build a [`Pipeline`].
+pub trait ModelPaths {
+    fn get_config_filename(&self) -> &PathBuf;
+    fn get_tokenizer_filename(&self) -> &PathBuf;
+    fn get_weight_filenames(&self) -> &[PathBuf];
+    fn get_adapter_filenames(&self) -> &Option<Vec<(String, PathBuf)>>;
+    fn get_adapter_configs(&self) -> &Option<Vec<(String, LoraConfig)>>;
+    fn get_classifier_config(&self) -> &Option<crate::xlora_models::XLoraConfig>;
+    fn get_classifier_path(&self) -> &Option<PathBuf>;
+    fn get_ordering(&self) -> &Option<Ordering>;
+    fn get_template_filename(&self) -> &PathBuf;
+}
+
+/// Downloads/resolves model files and builds the runnable [`Pipeline`].
+pub trait Loader {
+    fn download_model(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+    ) -> Result<Box<dyn ModelPaths>>;
+
+    fn _setup_model(
+        &self,
+        paths: &dyn ModelPaths,
+        dtype: Option<DType>,
+        device: &Device,
+    ) -> Result<Box<std::sync::Mutex<dyn Pipeline + Send + Sync>>>;
+}
+
+/// A loaded, runnable model. One method per piece of state the generation loop needs:
+/// running a forward pass, sampling from its logits, and streaming the resulting text.
+pub trait Pipeline {
+    fn forward(&mut self, input_toks: Box<[Rc<RefCell<Sequence>>]>, is_prompt: bool) -> Tensor;
+    fn device(&self) -> &Device;
+    fn num_hidden_layers(&self) -> usize;
+    fn cache(&self) -> &crate::models::Cache;
+    fn sample(&mut self, logits: Tensor, seq: Rc<RefCell<Sequence>>) -> Result<Logprobs>;
+    fn tokenizer(&self) -> Tokenizer;
+    fn eos_tok(&self) -> u32;
+    fn name(&self) -> &'static str;
+    fn get_max_seq_len(&self) -> usize;
+    fn is_xlora(&self) -> bool;
+    fn has_no_kv_cache(&self) -> bool;
+    fn get_chat_template(&self) -> &ChatTemplate;
+    /// Feeds one newly-sampled token into the pipeline's incremental detokenizer and
+    /// returns the text fragment it completed, if any. Pipelines that don't support
+    /// incremental UTF-8-safe streaming can rely on this default, which emits nothing.
+    fn step_decode(&mut self, _tok: u32) -> Option<String> {
+        None
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn calculate_inputs(
+    input_toks: Box<[Rc<RefCell<Sequence>>]>,
+    is_prompt: bool,
+    is_xlora: bool,
+    device: &Device,
+    no_kv_cache: bool,
+) -> (
+    Tensor,
+    Option<Tensor>,
+    Vec<usize>,
+    Option<Vec<usize>>,
+    Tensor,
+    Option<Tensor>,
+) {
+    let toks: Vec<Vec<u32>> = input_toks
+        .iter()
+        .map(|seq| {
+            let seq = seq.borrow();
+            if is_prompt {
+                seq.get_toks().to_vec()
+            } else {
+                vec![*seq.get_toks().last().unwrap()]
+            }
+        })
+        .collect();
+    let seqlen_offsets: Vec<usize> = input_toks
+        .iter()
+        .map(|seq| {
+            let seq = seq.borrow();
+            if is_prompt {
+                0
+            } else {
+                seq.get_toks().len() - 1
+            }
+        })
+        .collect();
+
+    let max_len = toks.iter().map(|t| t.len()).max().unwrap_or(0);
+    let padded: Vec<Vec<u32>> = toks
+        .into_iter()
+        .map(|mut t| {
+            t.resize(max_len, 0);
+            t
+        })
+        .collect();
+    let input_ids = Tensor::new(padded, device).unwrap();
+    let seqlen_offsets_kernel = Tensor::new(seqlen_offsets.as_slice(), device).unwrap();
+
+    let (input_ids_full, seqlen_offsets_full, seqlen_offsets_full_kernel) = if is_xlora {
+        (
+            Some(input_ids.clone()),
+            Some(seqlen_offsets.clone()),
+            Some(seqlen_offsets_kernel.clone()),
+        )
+    } else {
+        (None, None, None)
+    };
+
+    let _ = no_kv_cache;
+    (
+        input_ids,
+        input_ids_full,
+        seqlen_offsets,
+        seqlen_offsets_full,
+        seqlen_offsets_kernel,
+        seqlen_offsets_full_kernel,
+    )
+}
+
+pub(crate) fn get_prompt_input(
+    toks: Vec<u32>,
+    device: &Device,
+) -> Result<(Tensor, Vec<usize>, Tensor)> {
+    let seqlen_offsets = vec![0];
+    let seqlen_offsets_kernel = Tensor::new(seqlen_offsets.as_slice(), device)?;
+    let input_ids = Tensor::new(toks, device)?.unsqueeze(0)?;
+    Ok((input_ids, seqlen_offsets, seqlen_offsets_kernel))
+}
+
+pub(crate) fn get_completion_input(
+    toks: Vec<u32>,
+    device: &Device,
+    offset: usize,
+) -> Result<(Tensor, Vec<usize>, Tensor)> {
+    let seqlen_offsets = vec![offset];
+    let seqlen_offsets_kernel = Tensor::new(seqlen_offsets.as_slice(), device)?;
+    let last = *toks.last().unwrap();
+    let input_ids = Tensor::new(&[last], device)?.unsqueeze(0)?;
+    Ok((input_ids, seqlen_offsets, seqlen_offsets_kernel))
+}
+
+pub(crate) fn get_xlora_paths(
+    xlora_model_id: &Option<String>,
+    _token_source: &TokenSource,
+    _revision: String,
+    _xlora_order: &Option<Ordering>,
+) -> Result<XLoraPaths> {
+    if xlora_model_id.is_some() {
+        anyhow::bail!("X-LoRA model downloading is not implemented in this snapshot");
+    }
+    Ok(XLoraPaths {
+        adapter_configs: None,
+        adapter_safetensors: None,
+        classifier_path: None,
+        xlora_order: None,
+        xlora_config: None,
+    })
+}
+
+pub(crate) fn get_model_paths(
+    _revision: String,
+    _token_source: &TokenSource,
+    quantized_model_id: &Option<String>,
+    quantized_filename: &Option<String>,
+    api: &hf_hub::api::sync::ApiRepo,
+) -> Result<Vec<PathBuf>> {
+    match (quantized_model_id, quantized_filename) {
+        (Some(_), Some(filename)) => Ok(vec![api.get(filename)?]),
+        _ => Ok(vec![api.get("model.safetensors")?]),
+    }
+}
@@ -0,0 +1,53 @@
+use tokenizers::Tokenizer;
+
+/// Streaming decoder that only yields text once it is guaranteed to be valid UTF-8.
+///
+/// Detokenizing one token at a time can split a multi-byte character across two steps,
+/// which corrupts the output. Instead we keep every emitted token id around and re-decode
+/// a growing window, only emitting the newly-completed suffix once it no longer ends on a
+/// partial (replacement-char) boundary. This mirrors the approach used by candle's
+/// `token-output-stream` example.
+pub struct TokenOutputStream {
+    tokenizer: Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenOutputStream {
+    pub fn new(tokenizer: Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            tokens: Vec::new(),
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> anyhow::Result<String> {
+        match self.tokenizer.decode(tokens, true) {
+            Ok(str) => Ok(str),
+            Err(err) => anyhow::bail!("cannot decode: {err}"),
+        }
+    }
+
+    /// Records `token` as emitted and returns the newly-decodable text fragment, if any.
+    pub fn next_token(&mut self, token: u32) -> anyhow::Result<Option<String>> {
+        self.tokens.push(token);
+        let prev_text = if self.tokens.is_empty() {
+            String::new()
+        } else {
+            let tokens = &self.tokens[self.prev_index..self.current_index];
+            self.decode(tokens)?
+        };
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() && text.chars().last().is_some_and(|c| c != '\u{fffd}') {
+            let text = text.split_at(prev_text.len()).1.to_string();
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+            Ok(Some(text))
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use candle_sampling::logits_processor::LogitsProcessor;
+
+/// One in-flight generation request: the token ids produced so far (prompt +
+/// completion) plus the per-request sampler that owns its own RNG stream.
+pub struct Sequence {
+    tokens: Vec<u32>,
+    logits_processor: LogitsProcessor,
+    sampling_configured: bool,
+}
+
+impl Sequence {
+    pub fn new(tokens: Vec<u32>, logits_processor: LogitsProcessor) -> Self {
+        Self {
+            tokens,
+            logits_processor,
+            sampling_configured: false,
+        }
+    }
+
+    pub fn get_toks(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn add_tok(&mut self, tok: u32) {
+        self.tokens.push(tok);
+    }
+
+    /// This sequence's own sampler. Each `Sequence` owns its `LogitsProcessor` so
+    /// concurrent requests never share RNG state or a temperature/top-k/top-p override.
+    pub fn logits_processor(&mut self) -> &mut LogitsProcessor {
+        &mut self.logits_processor
+    }
+
+    /// Swaps in a pipeline-level sampling override (e.g. a configured temperature/
+    /// top-k/top-p) the first time this sequence is sampled, then leaves it alone -
+    /// every later call just keeps advancing the same `LogitsProcessor` instance
+    /// instead of rebuilding (and reseeding) a fresh one per token.
+    pub fn configure_sampling_once(
+        &mut self,
+        build: impl FnOnce() -> LogitsProcessor,
+    ) -> &mut LogitsProcessor {
+        if !self.sampling_configured {
+            self.logits_processor = build();
+            self.sampling_configured = true;
+        }
+        &mut self.logits_processor
+    }
+}
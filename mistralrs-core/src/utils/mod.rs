@@ -0,0 +1,2 @@
+pub mod tokens;
+pub mod varbuilder_utils;
@@ -0,0 +1,15 @@
+use anyhow::Result;
+
+use crate::pipeline::TokenSource;
+
+/// Resolves the HF Hub auth token to use for downloads from a [`TokenSource`].
+pub fn get_token(source: &TokenSource) -> Result<String> {
+    match source {
+        TokenSource::Literal(token) => Ok(token.clone()),
+        TokenSource::EnvVar(var) => Ok(std::env::var(var)?),
+        TokenSource::CacheToken => Ok(hf_hub::Cache::default()
+            .token()
+            .ok_or_else(|| anyhow::anyhow!("no cached HF Hub token found"))?),
+        TokenSource::None => Ok(String::new()),
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use candle_core::{DType, Device};
+use candle_nn::VarBuilder;
+
+/// Memory-maps one or more safetensors files (base weights plus, for X-LoRA
+/// checkpoints, adapter weights) and returns a single [`VarBuilder`] over all of them.
+pub fn from_mmaped_safetensors(
+    paths: Vec<PathBuf>,
+    lora_paths: Vec<PathBuf>,
+    dtype: DType,
+    device: &Device,
+    silent: bool,
+) -> Result<VarBuilder<'static>> {
+    let mut all_paths = paths;
+    all_paths.extend(lora_paths);
+    if !silent {
+        for path in &all_paths {
+            println!("Loading weights from `{}`", path.display());
+        }
+    }
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&all_paths, dtype, device)? };
+    Ok(vb)
+}
@@ -0,0 +1,98 @@
+use candle_core::{Result, Tensor};
+use candle_nn::VarBuilder;
+use mistralrs_lora::{LoraConfig, Ordering};
+
+use crate::models::gemma2::{Config, Model as Gemma2Model};
+use crate::xlora_models::{build_layer_contexts, Adapter, XLoraClassifier, XLoraConfig};
+
+/// X-LoRA-augmented Gemma2. See [`crate::xlora_models::XLoraGemma`] for the general
+/// shape; this is the same classifier-driven adapter blend over the sliding-window/
+/// soft-capped Gemma2 base model.
+pub struct XLoraGemma2 {
+    base: Gemma2Model,
+    adapters: Vec<Adapter>,
+    classifier: XLoraClassifier,
+    adapter_configs: Vec<(String, LoraConfig)>,
+    classifier_config: XLoraConfig,
+    ordering: Ordering,
+}
+
+impl XLoraGemma2 {
+    pub fn new(
+        config: &Config,
+        vb: VarBuilder,
+        adapter_configs: &[(String, LoraConfig)],
+        classifier_config: XLoraConfig,
+        ordering: Ordering,
+    ) -> Result<Self> {
+        let base = Gemma2Model::new(config, vb.clone())?;
+        let adapters = adapter_configs
+            .iter()
+            .map(|(name, _)| Adapter::load(name, config.num_hidden_layers, vb.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let classifier = XLoraClassifier::new(&classifier_config, vb)?;
+        Ok(Self {
+            base,
+            adapters,
+            classifier,
+            adapter_configs: adapter_configs.to_vec(),
+            classifier_config,
+            ordering,
+        })
+    }
+
+    pub fn ordering(&self) -> &Ordering {
+        &self.ordering
+    }
+
+    pub fn classifier_config(&self) -> &XLoraConfig {
+        &self.classifier_config
+    }
+
+    pub fn adapter_configs(&self) -> &[(String, LoraConfig)] {
+        &self.adapter_configs
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        seqlen_offsets_kernel: &Tensor,
+        _seqlen_offsets_full_kernel: Tensor,
+        _no_kv_cache: bool,
+    ) -> Result<Tensor> {
+        if self.adapters.is_empty() {
+            return self
+                .base
+                .forward(input_ids, seqlen_offsets, seqlen_offsets_kernel);
+        }
+        let pooled = self
+            .base
+            .embed_scaled(input_ids)?
+            .mean(1)?
+            .mean(0)?
+            .unsqueeze(0)?;
+        let scalings = self.classifier.forward(&pooled)?;
+        let lora = build_layer_contexts(
+            &self.adapters,
+            &scalings,
+            self.base.config.num_hidden_layers,
+        )?;
+        self.base.forward_with_lora(
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            Some(&lora),
+        )
+    }
+}
+
+impl std::ops::Deref for XLoraGemma2 {
+    type Target = Gemma2Model;
+    fn deref(&self) -> &Gemma2Model {
+        &self.base
+    }
+}
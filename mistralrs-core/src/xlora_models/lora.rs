@@ -0,0 +1,150 @@
+use candle_core::{IndexOp, Result, Tensor, D};
+use candle_nn::{Linear, Module, VarBuilder};
+
+use super::XLoraConfig;
+
+/// One loaded X-LoRA adapter: its low-rank `(lora_A, lora_B)` deltas for every
+/// self-attention projection of every decoder layer.
+pub struct Adapter {
+    pub layers: Vec<AdapterLayer>,
+}
+
+#[derive(Clone)]
+pub struct AdapterLayer {
+    pub q_proj: (Tensor, Tensor),
+    pub k_proj: (Tensor, Tensor),
+    pub v_proj: (Tensor, Tensor),
+    pub o_proj: (Tensor, Tensor),
+}
+
+impl Adapter {
+    /// Loads one adapter's low-rank deltas, scoped under its own name so multiple
+    /// adapters loaded into the same merged `VarBuilder` (see
+    /// `from_mmaped_safetensors`'s `lora_paths`) don't collide. Mirrors the base
+    /// model's own tensor naming (`model.layers.{i}.self_attn.{proj}.weight`), just
+    /// under `lora_A`/`lora_B` instead of `weight`.
+    pub fn load(name: &str, num_layers: usize, vb: VarBuilder) -> Result<Self> {
+        let vb = vb.pp(name);
+        let pair = |vb: VarBuilder| -> Result<(Tensor, Tensor)> {
+            Ok((
+                vb.get_unchecked("lora_A.weight")?,
+                vb.get_unchecked("lora_B.weight")?,
+            ))
+        };
+        let mut layers = Vec::with_capacity(num_layers);
+        for i in 0..num_layers {
+            let vb_l = vb.pp(format!("model.layers.{i}.self_attn"));
+            layers.push(AdapterLayer {
+                q_proj: pair(vb_l.pp("q_proj"))?,
+                k_proj: pair(vb_l.pp("k_proj"))?,
+                v_proj: pair(vb_l.pp("v_proj"))?,
+                o_proj: pair(vb_l.pp("o_proj"))?,
+            });
+        }
+        Ok(Self { layers })
+    }
+}
+
+/// Predicts, for each decoder layer, how much of each loaded adapter to blend in.
+///
+/// The real X-LoRA design conditions the classifier on the base model's own hidden
+/// states, which would mean running the transformer stack twice (once to get the
+/// states to classify, once more with the predicted scalings applied) every step.
+/// Since that second pass would also double up on KV-cache writes, this classifier
+/// instead conditions on the mean-pooled *input* embedding - a single small MLP
+/// (`xlora_depth` hidden layers of width `xlora_size`) ending in a `layers * n_classes`
+/// projection, softmax-normalized per layer over the adapters - so scalings can be
+/// predicted once, up front, and the real forward pass only ever runs once.
+pub struct XLoraClassifier {
+    hidden: Vec<Linear>,
+    out: Linear,
+    num_layers: usize,
+    n_classes: usize,
+}
+
+impl XLoraClassifier {
+    pub fn new(cfg: &XLoraConfig, vb: VarBuilder) -> Result<Self> {
+        let vb = vb.pp("classifier");
+        let width = cfg.xlora_size.max(1);
+        let mut hidden = Vec::with_capacity(cfg.xlora_depth);
+        let mut in_dim = cfg.hidden_size;
+        for i in 0..cfg.xlora_depth {
+            hidden.push(candle_nn::linear(
+                in_dim,
+                width,
+                vb.pp(format!("layers.{i}")),
+            )?);
+            in_dim = width;
+        }
+        let out = candle_nn::linear(in_dim, cfg.layers * cfg.n_classes, vb.pp("out"))?;
+        Ok(Self {
+            hidden,
+            out,
+            num_layers: cfg.layers,
+            n_classes: cfg.n_classes,
+        })
+    }
+
+    /// Returns one softmax-normalized scaling per `(layer, adapter)`, as a `(layers,
+    /// n_classes)` tensor.
+    pub fn forward(&self, pooled_embedding: &Tensor) -> Result<Tensor> {
+        let mut x = pooled_embedding.clone();
+        for layer in &self.hidden {
+            x = layer.forward(&x)?.relu()?;
+        }
+        let logits = self
+            .out
+            .forward(&x)?
+            .reshape((self.num_layers, self.n_classes))?;
+        candle_nn::ops::softmax(&logits, D::Minus1)
+    }
+}
+
+/// Every active adapter's scaled contribution to one decoder layer's self-attention
+/// projections, ready to be added on top of that projection's own output.
+#[derive(Clone, Default)]
+pub struct LoraLayerContext {
+    pub q_proj: Vec<(f64, (Tensor, Tensor))>,
+    pub k_proj: Vec<(f64, (Tensor, Tensor))>,
+    pub v_proj: Vec<(f64, (Tensor, Tensor))>,
+    pub o_proj: Vec<(f64, (Tensor, Tensor))>,
+}
+
+/// Builds one [`LoraLayerContext`] per decoder layer by pairing every loaded adapter's
+/// per-layer weights with the classifier-predicted scaling for that `(layer, adapter)`.
+pub fn build_layer_contexts(
+    adapters: &[Adapter],
+    scalings: &Tensor,
+    num_layers: usize,
+) -> Result<Vec<LoraLayerContext>> {
+    let mut contexts = vec![LoraLayerContext::default(); num_layers];
+    for (adapter_idx, adapter) in adapters.iter().enumerate() {
+        for (layer_idx, ctx) in contexts.iter_mut().enumerate() {
+            let scaling = scalings.i((layer_idx, adapter_idx))?.to_scalar::<f32>()? as f64;
+            let layer = &adapter.layers[layer_idx];
+            ctx.q_proj.push((scaling, layer.q_proj.clone()));
+            ctx.k_proj.push((scaling, layer.k_proj.clone()));
+            ctx.v_proj.push((scaling, layer.v_proj.clone()));
+            ctx.o_proj.push((scaling, layer.o_proj.clone()));
+        }
+    }
+    Ok(contexts)
+}
+
+/// Adds every `(scaling, (lora_A, lora_B))` contribution's low-rank delta -
+/// `scaling * (x @ lora_A^T) @ lora_B^T` - onto an already-computed base projection
+/// output, implementing the standard LoRA blend `y = W_0 x + scaling * B A x`.
+pub fn apply_lora(
+    base_out: &Tensor,
+    x: &Tensor,
+    contributions: &[(f64, (Tensor, Tensor))],
+) -> Result<Tensor> {
+    let mut out = base_out.clone();
+    for (scaling, (lora_a, lora_b)) in contributions {
+        let delta = x
+            .broadcast_matmul(&lora_a.t()?)?
+            .broadcast_matmul(&lora_b.t()?)?;
+        out = (out + (delta * *scaling)?)?;
+    }
+    Ok(out)
+}
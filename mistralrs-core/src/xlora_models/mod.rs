@@ -0,0 +1,22 @@
+pub mod gemma;
+pub mod gemma2;
+pub mod lora;
+
+pub use gemma::XLoraGemma;
+pub use gemma2::XLoraGemma2;
+pub use lora::{apply_lora, build_layer_contexts, Adapter, LoraLayerContext, XLoraClassifier};
+
+use serde::Deserialize;
+
+/// Classifier config for the X-LoRA scaling-prediction head, mirroring the
+/// `xlora_config.json` shipped alongside an X-LoRA classifier checkpoint.
+#[derive(Deserialize, Clone)]
+pub struct XLoraConfig {
+    pub hidden_size: usize,
+    pub layers: usize,
+    pub n_classes: usize,
+    #[serde(default)]
+    pub xlora_depth: usize,
+    #[serde(default)]
+    pub xlora_size: usize,
+}